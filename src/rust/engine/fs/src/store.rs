@@ -1,8 +1,14 @@
 use bazel_protos;
-use boxfuture::BoxFuture;
+use boxfuture::{Boxable, BoxFuture};
+use digest::{Digest as DigestTrait, FixedOutput};
+use futures::{future, Future};
 use protobuf::core::Message;
-use std::path::Path;
-use std::sync::Arc;
+use sha2::Sha256;
+use std::collections::HashSet;
+use std::fs;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 use hash::Fingerprint;
 use pool::ResettablePool;
@@ -26,50 +32,110 @@ impl Into<bazel_protos::remote_execution::Digest> for Digest {
   }
 }
 
+// Parse a Bazel remote execution Digest proto (as embedded in a FileNode/DirectoryNode) into our
+// own Digest, so callers that only have the proto on hand can still drive the size-aware load
+// path.
+fn digest_from_proto(digest: &bazel_protos::remote_execution::Digest) -> Result<Digest, String> {
+  let fingerprint = Fingerprint::from_hex_string(digest.get_hash()).map_err(|err| {
+    format!("Invalid digest hash {:?}: {}", digest.get_hash(), err)
+  })?;
+  Ok(Digest(fingerprint, digest.get_size_bytes() as usize))
+}
+
+///
+/// Controls whether a write-through to the remote ByteStore blocks the operation that triggered
+/// it, or is pushed in the background while the caller proceeds with the bytes it already has
+/// locally.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RemoteWriteMode {
+  Blocking,
+  FireAndForget,
+}
+
 ///
 /// A content-addressed store of file contents, and Directories.
 ///
-/// Currently, Store only stores things locally on disk, but in the future it will gain the ability
-/// to fetch files from remote content-addressable storage too.
+/// Store can be backed only by a local LMDB store, or additionally by a remote CAS. When both are
+/// present, Store acts as a two-layer cache: reads check the local store first and fall back to
+/// the remote on a miss (persisting what they find locally), and writes always land locally first
+/// and are then pushed to the remote, either blocking the caller or fire-and-forget depending on
+/// the configured RemoteWriteMode.
 ///
 #[derive(Clone)]
 pub struct Store {
   local: local::ByteStore,
+  remote: Option<(remote::ByteStore, RemoteWriteMode)>,
 }
 
 impl Store {
   pub fn new<P: AsRef<Path>>(path: P, pool: Arc<ResettablePool>) -> Result<Store, String> {
-    Ok(Store { local: local::ByteStore::new(path, pool)? })
+    Ok(Store {
+      local: local::ByteStore::new(path, pool)?,
+      remote: None,
+    })
+  }
+
+  pub fn with_remote<P: AsRef<Path>>(
+    path: P,
+    pool: Arc<ResettablePool>,
+    cas_address: &str,
+    instance_name: Option<String>,
+    chunk_size_bytes: usize,
+    write_mode: RemoteWriteMode,
+  ) -> Result<Store, String> {
+    Ok(Store {
+      local: local::ByteStore::new(path, pool)?,
+      remote: Some((
+        remote::ByteStore::new(cas_address, instance_name, chunk_size_bytes),
+        write_mode,
+      )),
+    })
   }
 
   pub fn store_file_bytes(&self, bytes: Vec<u8>) -> BoxFuture<Digest, String> {
-    self.local.store_file_bytes(bytes)
+    let store = self.clone();
+    self
+      .local
+      .store_file_bytes(bytes)
+      .map(move |digest| {
+        store.maybe_push_remote(digest.clone(), ByteSource::File);
+        digest
+      })
+      .to_boxed()
   }
 
   pub fn load_file_bytes_with<T: Send + 'static, F: Fn(&[u8]) -> T + Send + Sync + 'static>(
     &self,
-    fingerprint: Fingerprint,
+    digest: Digest,
     f: F,
   ) -> BoxFuture<Option<T>, String> {
-    self.local.load_file_bytes_with(
-      fingerprint.clone(),
-      Arc::new(f),
-    )
+    let f = Arc::new(f);
+    self.load_bytes_with(ByteSource::File, digest, f)
   }
 
   pub fn record_directory(
     &self,
     directory: &bazel_protos::remote_execution::Directory,
   ) -> BoxFuture<Digest, String> {
-    self.local.record_directory(directory)
+    let store = self.clone();
+    self
+      .local
+      .record_directory(directory)
+      .map(move |digest| {
+        store.maybe_push_remote(digest.clone(), ByteSource::Directory);
+        digest
+      })
+      .to_boxed()
   }
 
   pub fn load_directory(
     &self,
-    fingerprint: Fingerprint,
+    digest: Digest,
   ) -> BoxFuture<Option<bazel_protos::remote_execution::Directory>, String> {
-    self.local.load_directory_proto_bytes_with(
-      fingerprint,
+    self.load_bytes_with(
+      ByteSource::Directory,
+      digest,
       Arc::new(|bytes: &[u8]| {
         let mut directory = bazel_protos::remote_execution::Directory::new();
         directory.merge_from_bytes(bytes).expect(
@@ -79,6 +145,767 @@ impl Store {
       }),
     )
   }
+
+  // Loads a Directory proto straight from the local store, without falling back to the remote
+  // on a miss. Used by garbage_collect, which only ever has a bare root Fingerprint (no Digest)
+  // to start from, and which is inherently a local-store operation: what is or isn't live is
+  // decided by what the local store can already reach, not by what a configured remote happens
+  // to have.
+  fn load_directory_local(
+    &self,
+    fingerprint: Fingerprint,
+  ) -> BoxFuture<Option<bazel_protos::remote_execution::Directory>, String> {
+    self
+      .local
+      .load_directory_proto_bytes_with(
+        Digest(fingerprint, 0),
+        Arc::new(|bytes: &[u8]| {
+          let mut directory = bazel_protos::remote_execution::Directory::new();
+          directory.merge_from_bytes(bytes).expect(
+            "LMDB corruption: Directory bytes were not valid",
+          );
+          directory
+        }),
+      )
+      .to_boxed()
+  }
+
+  ///
+  /// Load bytes for the given digest, checking the local store first and falling back to the
+  /// remote store (if configured) on a miss. The full Digest (not just its Fingerprint) is needed
+  /// so that a remote fetch can build a correctly-sized ByteStream read request. Bytes fetched
+  /// remotely are verified against the requested Digest and persisted locally before the caller's
+  /// closure runs, so a subsequent load of the same digest is served locally.
+  ///
+  fn load_bytes_with<T: Send + 'static, F: Fn(&[u8]) -> T + Send + Sync + 'static>(
+    &self,
+    source: ByteSource,
+    digest: Digest,
+    f: Arc<F>,
+  ) -> BoxFuture<Option<T>, String> {
+    let store = self.clone();
+    let f2 = f.clone();
+    let fingerprint = digest.0;
+    source
+      .load_local(&self.local, digest.clone(), f)
+      .and_then(move |maybe_local| {
+        if maybe_local.is_some() {
+          return future::ok(maybe_local).to_boxed();
+        }
+        let (remote, _write_mode) = match store.remote {
+          Some(ref remote) => remote.clone(),
+          None => return future::ok(None).to_boxed(),
+        };
+        source
+          .load_remote(&remote, digest.clone(), Arc::new(|bytes: &[u8]| bytes.to_vec()))
+          .and_then(move |maybe_bytes| match maybe_bytes {
+            None => future::ok(None).to_boxed(),
+            Some(bytes) => {
+              let actual_fingerprint = {
+                let mut hasher = Sha256::default();
+                hasher.input(&bytes);
+                Fingerprint::from_bytes_unsafe(hasher.fixed_result().as_slice())
+              };
+              if actual_fingerprint != fingerprint || bytes.len() != digest.1 {
+                return future::err(format!(
+                  "Remote CAS gave wrong content for digest {:?}: actually had fingerprint {} \
+                   and length {}",
+                  digest,
+                  actual_fingerprint,
+                  bytes.len()
+                )).to_boxed();
+              }
+              source
+                .store_local(&store.local, bytes.clone())
+                .map(move |_| Some(f2(&bytes)))
+                .to_boxed()
+            }
+          })
+          .to_boxed()
+      })
+      .to_boxed()
+  }
+
+  fn maybe_push_remote(&self, digest: Digest, source: ByteSource) {
+    let (remote, write_mode) = match self.remote {
+      Some(ref remote) => remote.clone(),
+      None => return,
+    };
+    let local = self.local.clone();
+    let push = source
+      .load_local(&local, digest.clone(), Arc::new(|bytes: &[u8]| bytes.to_vec()))
+      .and_then(move |maybe_bytes| match maybe_bytes {
+        None => future::ok(()).to_boxed(),
+        Some(bytes) => source.store_remote(&remote, bytes).map(|_| ()).to_boxed(),
+      });
+    match write_mode {
+      RemoteWriteMode::Blocking => {
+        if let Err(err) = push.wait() {
+          error!(
+            "Error pushing {} to the remote CAS in blocking write mode: {}",
+            digest.0,
+            err
+          );
+        }
+      }
+      RemoteWriteMode::FireAndForget => push.forget(),
+    }
+  }
+
+  ///
+  /// Like record_directory, but first validates that the Directory is canonical (its files and
+  /// directories are each sorted by name, and no name is repeated across files, directories and
+  /// symlinks) and that every DirectoryNode it references is already present in the store -
+  /// recursively, down through the full transitive closure. This guarantees that once a root
+  /// records successfully, every descendant it names is already safely in the store, which is a
+  /// prerequisite for safely uploading the tree remotely or garbage collecting by root.
+  ///
+  pub fn record_directory_recursively(
+    &self,
+    directory: &bazel_protos::remote_execution::Directory,
+  ) -> BoxFuture<Digest, String> {
+    let store = self.clone();
+    let directory = directory.clone();
+    self
+      .validate_directory_closure(directory.clone())
+      .and_then(move |()| store.record_directory(&directory))
+      .to_boxed()
+  }
+
+  fn validate_directory_closure(
+    &self,
+    directory: bazel_protos::remote_execution::Directory,
+  ) -> BoxFuture<(), String> {
+    if let Err(err) = validate_directory_canonical(&directory) {
+      return future::err(err).to_boxed();
+    }
+
+    let store = self.clone();
+    let directories_closed = future::join_all(directory.get_directories().iter().map(move |node| {
+      let store = store.clone();
+      let name = node.get_name().to_string();
+      let hash = node.get_digest().get_hash().to_string();
+      let size_bytes = node.get_digest().get_size_bytes() as usize;
+      future::result(Fingerprint::from_hex_string(&hash).map_err(|err| {
+        format!(
+          "Directory node {:?} has an invalid digest hash {:?}: {}",
+          name,
+          hash,
+          err
+        )
+      })).and_then(move |fingerprint| {
+        let store2 = store.clone();
+        let name2 = name.clone();
+        let digest = Digest(fingerprint, size_bytes);
+        store.load_directory(digest.clone()).and_then(move |maybe_child| match maybe_child {
+          None => future::err(format!(
+            "Directory node {:?} references a Directory that is not present in the store: {}",
+            name2,
+            digest.0
+          )).to_boxed(),
+          Some(child) => store2.validate_directory_closure(child),
+        })
+      })
+        .to_boxed()
+    }).collect::<Vec<_>>()).map(|_| ());
+
+    let store = self.clone();
+    let files_present = future::join_all(directory.get_files().iter().map(move |node| {
+      let store = store.clone();
+      let name = node.get_name().to_string();
+      let hash = node.get_digest().get_hash().to_string();
+      let size_bytes = node.get_digest().get_size_bytes() as usize;
+      future::result(Fingerprint::from_hex_string(&hash).map_err(|err| {
+        format!(
+          "File node {:?} has an invalid digest hash {:?}: {}",
+          name,
+          hash,
+          err
+        )
+      })).and_then(move |fingerprint| {
+        let digest = Digest(fingerprint, size_bytes);
+        store
+          .load_file_bytes_with(digest.clone(), |_| ())
+          .and_then(move |maybe_file| match maybe_file {
+            None => Err(format!(
+              "File node {:?} references a file that is not present in the store: {}",
+              name,
+              digest.0
+            )),
+            Some(()) => Ok(()),
+          })
+      })
+        .to_boxed()
+    }).collect::<Vec<_>>()).map(|_| ());
+
+    directories_closed.join(files_present).map(|((), ())| ()).to_boxed()
+  }
+}
+
+///
+/// Check that directory's files and subdirectories are each sorted by name and that no name is
+/// repeated across files, directories and symlinks. Does not recurse: validate_directory_closure
+/// is responsible for checking this for every Directory in the tree.
+///
+fn validate_directory_canonical(
+  directory: &bazel_protos::remote_execution::Directory,
+) -> Result<(), String> {
+  let mut seen_names: HashSet<&str> = HashSet::new();
+
+  let mut last_file_name = None;
+  for file in directory.get_files() {
+    let name = file.get_name();
+    if let Some(last) = last_file_name {
+      if name <= last {
+        return Err(format!(
+          "Directory's files are not sorted by name: {:?} follows {:?}",
+          name,
+          last
+        ));
+      }
+    }
+    last_file_name = Some(name);
+    if !seen_names.insert(name) {
+      return Err(format!("Directory has a duplicate name: {:?}", name));
+    }
+  }
+
+  let mut last_directory_name = None;
+  for child in directory.get_directories() {
+    let name = child.get_name();
+    if let Some(last) = last_directory_name {
+      if name <= last {
+        return Err(format!(
+          "Directory's subdirectories are not sorted by name: {:?} follows {:?}",
+          name,
+          last
+        ));
+      }
+    }
+    last_directory_name = Some(name);
+    if !seen_names.insert(name) {
+      return Err(format!("Directory has a duplicate name: {:?}", name));
+    }
+  }
+
+  for symlink in directory.get_symlinks() {
+    let name = symlink.get_name();
+    if !seen_names.insert(name) {
+      return Err(format!("Directory has a duplicate name: {:?}", name));
+    }
+  }
+
+  Ok(())
+}
+
+// The result of ingesting a single directory entry: which of the three Directory proto
+// collections it belongs in is only known once we've statted it.
+enum IngestedNode {
+  File(bazel_protos::remote_execution::FileNode),
+  Directory(bazel_protos::remote_execution::DirectoryNode),
+  Symlink(bazel_protos::remote_execution::SymlinkNode),
+}
+
+impl Store {
+  ///
+  /// Save the directory tree rooted at root into the Store as content-addressed file
+  /// blobs and Directory protos, returning the Digest of the root Directory. This is the inverse
+  /// of materialize_directory. Symlinks are captured as SymlinkNodes rather than followed.
+  /// Sibling entries are ingested concurrently over the Store's worker pool.
+  ///
+  pub fn ingest_path<P: AsRef<Path>>(&self, root: P) -> BoxFuture<Digest, String> {
+    self.ingest_directory(root.as_ref().to_owned())
+  }
+
+  fn ingest_directory(&self, path: PathBuf) -> BoxFuture<Digest, String> {
+    let store_for_entries = self.clone();
+    let store_for_record = self.clone();
+    let list_path = path.clone();
+    self
+      .local
+      .pool()
+      .spawn_fn(move || {
+        let mut entries: Vec<fs::DirEntry> = fs::read_dir(&list_path)
+          .map_err(|e| format!("Error listing directory {:?}: {}", list_path, e))?
+          .collect::<Result<Vec<_>, _>>()
+          .map_err(|e| format!("Error reading an entry of directory {:?}: {}", list_path, e))?;
+        entries.sort_by_key(|entry| entry.file_name());
+        Ok(entries)
+      })
+      .and_then(move |entries| {
+        future::join_all(
+          entries
+            .into_iter()
+            .map(|entry| store_for_entries.clone().ingest_dir_entry(entry))
+            .collect::<Vec<_>>(),
+        )
+      })
+      .and_then(move |nodes| {
+        let mut directory = bazel_protos::remote_execution::Directory::new();
+        for node in nodes {
+          match node {
+            IngestedNode::File(file_node) => directory.mut_files().push(file_node),
+            IngestedNode::Directory(directory_node) => {
+              directory.mut_directories().push(directory_node)
+            }
+            IngestedNode::Symlink(symlink_node) => directory.mut_symlinks().push(symlink_node),
+          }
+        }
+        store_for_record.record_directory(&directory)
+      })
+      .to_boxed()
+  }
+
+  fn ingest_dir_entry(self, entry: fs::DirEntry) -> BoxFuture<IngestedNode, String> {
+    let store = self;
+    let path = entry.path();
+    let name = entry.file_name().to_string_lossy().into_owned();
+    let stat_path = path.clone();
+    store
+      .local
+      .pool()
+      .spawn_fn(move || {
+        fs::symlink_metadata(&stat_path)
+          .map_err(|e| format!("Error statting {:?}: {}", stat_path, e))
+      })
+      .and_then(move |metadata| {
+        if metadata.file_type().is_symlink() {
+          future::result(
+            fs::read_link(&path).map_err(|e| format!("Error reading symlink {:?}: {}", path, e)),
+          ).map(move |target| {
+            let mut node = bazel_protos::remote_execution::SymlinkNode::new();
+            node.set_name(name);
+            node.set_target(target.to_string_lossy().into_owned());
+            IngestedNode::Symlink(node)
+          })
+            .to_boxed()
+        } else if metadata.is_dir() {
+          store
+            .ingest_directory(path)
+            .map(move |digest| {
+              let mut node = bazel_protos::remote_execution::DirectoryNode::new();
+              node.set_name(name);
+              node.set_digest(digest.into());
+              IngestedNode::Directory(node)
+            })
+            .to_boxed()
+        } else {
+          let is_executable = metadata.permissions().mode() & 0o100 != 0;
+          future::result(
+            fs::read(&path).map_err(|e| format!("Error reading file {:?}: {}", path, e)),
+          ).and_then(move |bytes| store.store_file_bytes(bytes))
+            .map(move |digest| {
+              let mut node = bazel_protos::remote_execution::FileNode::new();
+              node.set_name(name);
+              node.set_digest(digest.into());
+              node.set_is_executable(is_executable);
+              IngestedNode::File(node)
+            })
+            .to_boxed()
+        }
+      })
+      .to_boxed()
+  }
+}
+
+impl Store {
+  ///
+  /// Recreate the Directory tree rooted at digest on disk at destination: files are written
+  /// with their recorded executable bit, subdirectories are created and recursed into, and
+  /// symlink nodes are recreated as symlinks. This is the inverse of ingest_path, and is what the
+  /// execution engine uses to stage inputs for a process. Independent subtrees are materialized
+  /// concurrently over the Store's worker pool.
+  ///
+  /// If any digest referenced by the tree (including the root) is missing from the store, this
+  /// fails cleanly and destination is left exactly as it was found: materialization happens in a
+  /// temporary sibling of destination, which is only renamed into place once the whole tree has
+  /// been written, and is removed on any failure.
+  ///
+  pub fn materialize_directory(
+    &self,
+    destination: PathBuf,
+    digest: Digest,
+  ) -> BoxFuture<(), String> {
+    let store = self.clone();
+    self
+      .load_directory(digest.clone())
+      .and_then(move |maybe_directory| {
+        maybe_directory.ok_or_else(|| {
+          format!(
+            "Directory with digest {} was not found in the store",
+            digest.0
+          )
+        })
+      })
+      .and_then(move |directory| store.materialize_directory_atomically(destination, directory))
+      .to_boxed()
+  }
+
+  fn materialize_directory_atomically(
+    &self,
+    destination: PathBuf,
+    directory: bazel_protos::remote_execution::Directory,
+  ) -> BoxFuture<(), String> {
+    let store = self.clone();
+    let tmp_file_name = format!(
+      ".materializing.{}",
+      destination
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("root")
+    );
+    let tmp_destination = match destination.parent() {
+      Some(parent) => parent.join(tmp_file_name),
+      None => PathBuf::from(tmp_file_name),
+    };
+    let build_destination = tmp_destination.clone();
+    let rename_from = tmp_destination.clone();
+    let rename_to = destination.clone();
+    let cleanup_destination = tmp_destination.clone();
+    let pool = self.local.pool();
+
+    self
+      .local
+      .pool()
+      .spawn_fn(move || {
+        // Remove any stale temporary tree left behind by a previous failed materialization.
+        let _ = fs::remove_dir_all(&tmp_destination);
+        Ok(()) as Result<(), String>
+      })
+      .and_then(move |()| store.materialize_directory_helper(build_destination, directory))
+      .and_then(move |()| {
+        pool.spawn_fn(move || {
+          fs::rename(&rename_from, &rename_to).map_err(|e| {
+            format!(
+              "Error moving materialized directory from {:?} to {:?}: {}",
+              rename_from,
+              rename_to,
+              e
+            )
+          })
+        })
+      })
+      .or_else(move |err| {
+        let _ = fs::remove_dir_all(&cleanup_destination);
+        future::err(err)
+      })
+      .to_boxed()
+  }
+
+  fn materialize_directory_helper(
+    &self,
+    destination: PathBuf,
+    directory: bazel_protos::remote_execution::Directory,
+  ) -> BoxFuture<(), String> {
+    let store = self.clone();
+    let mkdir_destination = destination.clone();
+    self
+      .local
+      .pool()
+      .spawn_fn(move || {
+        fs::create_dir_all(&mkdir_destination)
+          .map_err(|e| format!("Error creating directory {:?}: {}", mkdir_destination, e))
+      })
+      .and_then(move |()| {
+        let mut tasks: Vec<BoxFuture<(), String>> = Vec::new();
+
+        for file_node in directory.get_files() {
+          let store = store.clone();
+          let path = destination.join(file_node.get_name());
+          let digest = digest_from_proto(file_node.get_digest());
+          let is_executable = file_node.get_is_executable();
+          tasks.push(store.materialize_file(path, digest, is_executable));
+        }
+
+        for directory_node in directory.get_directories() {
+          let store = store.clone();
+          let store2 = store.clone();
+          let path = destination.join(directory_node.get_name());
+          let digest = digest_from_proto(directory_node.get_digest());
+          tasks.push(
+            store
+              .load_directory_node(digest)
+              .and_then(move |child| store2.materialize_directory_helper(path, child))
+              .to_boxed(),
+          );
+        }
+
+        for symlink_node in directory.get_symlinks() {
+          let path = destination.join(symlink_node.get_name());
+          let target = symlink_node.get_target().to_string();
+          tasks.push(
+            store
+              .local
+              .pool()
+              .spawn_fn(move || {
+                symlink(&target, &path)
+                  .map_err(|e| format!("Error creating symlink at {:?}: {}", path, e))
+              })
+              .to_boxed(),
+          );
+        }
+
+        future::join_all(tasks).map(|_| ())
+      })
+      .to_boxed()
+  }
+
+  fn materialize_file(
+    &self,
+    path: PathBuf,
+    digest: Result<Digest, String>,
+    is_executable: bool,
+  ) -> BoxFuture<(), String> {
+    let digest = match digest {
+      Ok(digest) => digest,
+      Err(err) => return future::err(err).to_boxed(),
+    };
+    let pool = self.local.pool();
+    self
+      .load_file_bytes_with(digest.clone(), |bytes: &[u8]| bytes.to_vec())
+      .and_then(move |maybe_bytes| {
+        maybe_bytes.ok_or_else(|| {
+          format!("File with digest {} was not found in the store", digest.0)
+        })
+      })
+      .and_then(move |bytes| {
+        pool.spawn_fn(move || {
+          fs::write(&path, &bytes).map_err(|e| format!("Error writing file {:?}: {}", path, e))?;
+          let mut permissions = fs::metadata(&path)
+            .map_err(|e| format!("Error statting {:?}: {}", path, e))?
+            .permissions();
+          permissions.set_mode(if is_executable { 0o755 } else { 0o644 });
+          fs::set_permissions(&path, permissions)
+            .map_err(|e| format!("Error setting permissions on {:?}: {}", path, e))
+        })
+      })
+      .to_boxed()
+  }
+
+  // Loads the Directory referenced by a DirectoryNode's Digest, failing cleanly (rather than
+  // leaving a half-written tree on disk) if it is not present in the store.
+  fn load_directory_node(
+    &self,
+    digest: Result<Digest, String>,
+  ) -> BoxFuture<bazel_protos::remote_execution::Directory, String> {
+    let digest = match digest {
+      Ok(digest) => digest,
+      Err(err) => return future::err(err).to_boxed(),
+    };
+    self
+      .load_directory(digest.clone())
+      .and_then(move |maybe_directory| {
+        maybe_directory.ok_or_else(|| {
+          format!(
+            "Directory with digest {} was not found in the store",
+            digest.0
+          )
+        })
+      })
+      .to_boxed()
+  }
+}
+
+///
+/// The result of a garbage_collect pass: how many file and Directory values were (or, in a
+/// dry run, would be) removed, and how many bytes of LMDB value storage that reclaims.
+///
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct GarbageCollectionSummary {
+  pub files_removed: usize,
+  pub file_bytes_reclaimed: usize,
+  pub directories_removed: usize,
+  pub directory_bytes_reclaimed: usize,
+}
+
+impl Store {
+  ///
+  /// Mark-and-sweep garbage collection: starting from roots (Fingerprints of root Directory
+  /// protos), walk the transitive closure of every DirectoryNode and FileNode digest they
+  /// reference to build the live set, then delete every file and Directory value in the store
+  /// that isn't in it. With dry_run, reports what would be deleted without deleting anything.
+  ///
+  /// The mark walk runs as an ordinary future chain, without holding gc_lock, so that a pool
+  /// thread is never blocked waiting on pool-scheduled work (mark_live's own loads are serviced
+  /// by this same bounded pool; holding gc_lock across the walk would mean blocking one pool
+  /// thread on work that needs a free pool thread to ever complete, deadlocking the pool). Only
+  /// the sweep - the actual, synchronous LMDB deletion - runs while holding gc_lock in write mode,
+  /// which excludes every store_file_bytes/record_directory call for the sweep's duration (they
+  /// take gc_lock in read mode around each write), so the sweep's own transaction can't observe a
+  /// value disappearing or a half-written value concurrently with its own read. A write that
+  /// commits after the mark walk has already read past it but before the sweep begins is still
+  /// invisible to the live set computed during the walk, and will be swept; callers that need a
+  /// stronger guarantee should ensure no writes race with garbage_collect at a higher level.
+  ///
+  pub fn garbage_collect(
+    &self,
+    roots: &[Fingerprint],
+    dry_run: bool,
+  ) -> BoxFuture<GarbageCollectionSummary, String> {
+    let store = self.clone();
+    let roots = roots.to_vec();
+    let gc_lock = self.local.gc_lock();
+
+    let live_directories = Arc::new(Mutex::new(HashSet::new()));
+    let live_files = Arc::new(Mutex::new(HashSet::new()));
+
+    // The mark walk is composed as a single future chain, the same way mark_live itself already
+    // composes its own recursion, so that it runs by driving the futures forward rather than by
+    // blocking a pool thread on them. mark_live's loads are themselves serviced by this same pool
+    // (via spawn_fn in local::ByteStore), so a pool thread that blocked here waiting on them could
+    // starve the pool of the very thread needed to service that load, deadlocking the whole pass.
+    future::join_all(
+      roots
+        .iter()
+        .cloned()
+        .map(|root| store.mark_live(root, live_directories.clone(), live_files.clone()))
+        .collect::<Vec<_>>(),
+    ).and_then(move |_| {
+        let live_directories = Arc::try_unwrap(live_directories)
+          .unwrap_or_else(|_| panic!("Some mark_live future outlived garbage_collect"))
+          .into_inner()
+          .unwrap();
+        let live_files = Arc::try_unwrap(live_files)
+          .unwrap_or_else(|_| panic!("Some mark_live future outlived garbage_collect"))
+          .into_inner()
+          .unwrap();
+
+        // Only the sweep itself - the genuinely blocking, synchronous LMDB work - runs inside a
+        // pool thread, and only for the sweep's duration is gc_lock held in write mode, so this
+        // terminal closure does not wait on any other pool-scheduled work while holding it.
+        store.local.pool().spawn_fn(move || {
+          let _write_guard = gc_lock
+            .write()
+            .map_err(|err| format!("Store gc_lock was poisoned: {}", err))?;
+
+          let (file_stats, directory_stats) =
+            store.local.garbage_collect(&live_files, &live_directories, dry_run)?;
+          Ok(GarbageCollectionSummary {
+            files_removed: file_stats.keys_removed,
+            file_bytes_reclaimed: file_stats.bytes_reclaimed,
+            directories_removed: directory_stats.keys_removed,
+            directory_bytes_reclaimed: directory_stats.bytes_reclaimed,
+          })
+        })
+      })
+      .to_boxed()
+  }
+
+  // Recursively walks the Directory tree rooted at fingerprint, recording every Directory
+  // fingerprint it visits and every file Fingerprint any of them reference. Shared subtrees (the
+  // same Directory reachable from more than one root) are only walked once.
+  fn mark_live(
+    &self,
+    fingerprint: Fingerprint,
+    live_directories: Arc<Mutex<HashSet<Fingerprint>>>,
+    live_files: Arc<Mutex<HashSet<Fingerprint>>>,
+  ) -> BoxFuture<(), String> {
+    {
+      let mut live_directories = live_directories.lock().unwrap();
+      if !live_directories.insert(fingerprint) {
+        return future::ok(()).to_boxed();
+      }
+    }
+
+    let store = self.clone();
+    self
+      .load_directory_local(fingerprint)
+      .and_then(move |maybe_directory| {
+        maybe_directory.ok_or_else(|| {
+          format!(
+            "Directory with digest {} referenced during garbage collection was not found in \
+             the store",
+            fingerprint
+          )
+        })
+      })
+      .and_then(move |directory| {
+        {
+          let mut live_files = live_files.lock().unwrap();
+          for file_node in directory.get_files() {
+            if let Ok(file_fingerprint) =
+              Fingerprint::from_hex_string(file_node.get_digest().get_hash())
+            {
+              live_files.insert(file_fingerprint);
+            }
+          }
+        }
+
+        future::join_all(
+          directory
+            .get_directories()
+            .iter()
+            .filter_map(|node| Fingerprint::from_hex_string(node.get_digest().get_hash()).ok())
+            .map(|child_fingerprint| {
+              store.mark_live(child_fingerprint, live_directories.clone(), live_files.clone())
+            })
+            .collect::<Vec<_>>(),
+        ).map(|_| ())
+      })
+      .to_boxed()
+  }
+}
+
+///
+/// Which of the two logical namespaces (file contents vs Directory protos) an operation is
+/// reading or writing, so load/store code can be written once and shared between them.
+///
+#[derive(Clone, Copy)]
+enum ByteSource {
+  File,
+  Directory,
+}
+
+impl ByteSource {
+  fn load_local<T: Send + 'static, F: Fn(&[u8]) -> T + Send + Sync + 'static>(
+    &self,
+    local: &local::ByteStore,
+    digest: Digest,
+    f: Arc<F>,
+  ) -> BoxFuture<Option<T>, String> {
+    match *self {
+      ByteSource::File => local.load_file_bytes_with(digest, f),
+      ByteSource::Directory => local.load_directory_proto_bytes_with(digest, f),
+    }
+  }
+
+  fn store_local(&self, local: &local::ByteStore, bytes: Vec<u8>) -> BoxFuture<Digest, String> {
+    match *self {
+      ByteSource::File => local.store_file_bytes(bytes),
+      ByteSource::Directory => {
+        let mut directory = bazel_protos::remote_execution::Directory::new();
+        directory.merge_from_bytes(&bytes).expect(
+          "LMDB corruption: Directory bytes were not valid",
+        );
+        local.record_directory(&directory)
+      }
+    }
+  }
+
+  fn load_remote<T: Send + 'static, F: Fn(&[u8]) -> T + Send + Sync + 'static>(
+    &self,
+    remote: &remote::ByteStore,
+    digest: Digest,
+    f: Arc<F>,
+  ) -> BoxFuture<Option<T>, String> {
+    match *self {
+      ByteSource::File => remote.load_file_bytes_with(digest, f),
+      ByteSource::Directory => remote.load_directory_proto_bytes_with(digest, f),
+    }
+  }
+
+  fn store_remote(&self, remote: &remote::ByteStore, bytes: Vec<u8>) -> BoxFuture<Digest, String> {
+    match *self {
+      ByteSource::File => remote.store_file_bytes(bytes),
+      ByteSource::Directory => {
+        let mut directory = bazel_protos::remote_execution::Directory::new();
+        directory.merge_from_bytes(&bytes).expect(
+          "LMDB corruption: Directory bytes were not valid",
+        );
+        remote.record_directory(&directory)
+      }
+    }
+  }
 }
 
 ///
@@ -89,9 +916,14 @@ impl Store {
 pub trait ByteStore {
   fn store_file_bytes(&self, bytes: Vec<u8>) -> BoxFuture<Digest, String>;
 
+  ///
+  /// Load the bytes for digest. Takes the full Digest, rather than just its Fingerprint, because a
+  /// remote CAS read needs the size up-front to build a correct ByteStream resource name; a local
+  /// implementation is free to ignore digest.1.
+  ///
   fn load_file_bytes_with<T: Send + 'static, F: Fn(&[u8]) -> T + Send + Sync + 'static>(
     &self,
-    fingerprint: Fingerprint,
+    digest: Digest,
     f: Arc<F>,
   ) -> BoxFuture<Option<T>, String>;
 
@@ -105,7 +937,7 @@ pub trait ByteStore {
   ///
   fn load_directory_proto_bytes_with<T: Send + 'static, F: Fn(&[u8]) -> T + Send + Sync + 'static>(
     &self,
-    fingerprint: Fingerprint,
+    digest: Digest,
     f: Arc<F>,
   ) -> BoxFuture<Option<T>, String>;
 }
@@ -118,17 +950,133 @@ mod local {
   use digest::{Digest as DigestTrait, FixedOutput};
   use futures::{future, Future};
   use futures_cpupool::CpuFuture;
-  use lmdb::{Database, DatabaseFlags, Environment, NO_OVERWRITE, Transaction};
+  use lmdb::{Cursor, Database, DatabaseFlags, Environment, NO_OVERWRITE, RwTransaction,
+             Transaction};
   use lmdb::Error::{KeyExist, NotFound};
   use protobuf::core::Message;
   use sha2::Sha256;
+  use std::collections::HashSet;
   use std::error::Error;
   use std::path::Path;
-  use std::sync::Arc;
+  use std::sync::{Arc, RwLock};
 
   use hash::Fingerprint;
   use pool::ResettablePool;
 
+  ///
+  /// Content-defined chunking parameters for splitting large file blobs into independently
+  /// content-addressed pieces, so a small edit to a large file only re-stores the chunks that
+  /// actually changed instead of the whole file.
+  ///
+  #[derive(Clone, Copy, Debug)]
+  pub struct ChunkingOptions {
+    pub min_size_bytes: usize,
+    pub avg_size_bytes: usize,
+    pub max_size_bytes: usize,
+  }
+
+  impl Default for ChunkingOptions {
+    fn default() -> ChunkingOptions {
+      ChunkingOptions {
+        min_size_bytes: 256 * 1024,
+        avg_size_bytes: 1024 * 1024,
+        max_size_bytes: 4 * 1024 * 1024,
+      }
+    }
+  }
+
+  // A manifest recording the ordered chunks that a chunked file was split into, plus its total
+  // length, stored in manifest_store (not file_store) under the Fingerprint of the whole
+  // (unchunked) plaintext. Keeping manifests in their own database, rather than tagging them with
+  // a magic prefix inside file_store, means "is this a manifest" is answered by which database a
+  // fingerprint was found in, not by sniffing the value - so there is no content a plain file
+  // could coincidentally contain that would make it misread as a manifest.
+  const FINGERPRINT_SIZE_BYTES: usize = 32;
+
+  fn encode_manifest(chunk_fingerprints: &[Fingerprint], total_len: usize) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(8 + chunk_fingerprints.len() * FINGERPRINT_SIZE_BYTES);
+    bytes.extend_from_slice(&(total_len as u64).to_le_bytes());
+    for chunk_fingerprint in chunk_fingerprints {
+      bytes.extend_from_slice(chunk_fingerprint.as_bytes());
+    }
+    bytes
+  }
+
+  fn decode_manifest(bytes: &[u8]) -> Result<(Vec<Fingerprint>, usize), String> {
+    if bytes.len() < 8 {
+      return Err(format!(
+        "LMDB corruption: manifest value is only {} bytes, too short to contain a length",
+        bytes.len()
+      ));
+    }
+    let mut len_bytes = [0u8; 8];
+    len_bytes.copy_from_slice(&bytes[..8]);
+    let total_len = u64::from_le_bytes(len_bytes) as usize;
+    let chunk_bytes = &bytes[8..];
+    if chunk_bytes.len() % FINGERPRINT_SIZE_BYTES != 0 {
+      return Err(format!(
+        "LMDB corruption: manifest chunk list is {} bytes, not a multiple of {}",
+        chunk_bytes.len(),
+        FINGERPRINT_SIZE_BYTES
+      ));
+    }
+    let chunk_fingerprints = chunk_bytes
+      .chunks(FINGERPRINT_SIZE_BYTES)
+      .map(|chunk| Fingerprint::from_bytes_unsafe(chunk))
+      .collect();
+    Ok((chunk_fingerprints, total_len))
+  }
+
+  // A table of pseudo-random 64-bit values indexed by byte value, used to roll a "gear hash" over
+  // the input: hash = (hash << 1) + GEAR[byte]. This is the standard gear-hash construction used
+  // by FastCDC-style content-defined chunkers.
+  fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut seed: u64 = 0x9E3779B97F4A7C15;
+    for (i, slot) in table.iter_mut().enumerate() {
+      seed ^= seed << 13;
+      seed ^= seed >> 7;
+      seed ^= seed << 17;
+      *slot = seed.wrapping_add(i as u64);
+    }
+    table
+  }
+
+  //
+  // Find the end offsets of content-defined chunks in bytes: a cut is made once a chunk reaches
+  // min_size_bytes and the low log2(avg_size_bytes) bits of its rolling gear hash (avg_size_bytes
+  // rounded up to a power of two) are all zero, or once it reaches max_size_bytes, whichever comes
+  // first. Returns an empty Vec if bytes is
+  // empty, and a single cut at bytes.len() if bytes never split (i.e. is not actually chunked).
+  //
+  fn chunk_cut_points(bytes: &[u8], options: ChunkingOptions) -> Vec<usize> {
+    if bytes.is_empty() {
+      return vec![];
+    }
+    let table = gear_table();
+    let mask = (options.avg_size_bytes as u64)
+      .next_power_of_two()
+      .wrapping_sub(1);
+    let mut cuts = Vec::new();
+    let mut hash: u64 = 0;
+    let mut chunk_start = 0;
+    for (i, &byte) in bytes.iter().enumerate() {
+      hash = (hash << 1).wrapping_add(table[byte as usize]);
+      let chunk_len = i + 1 - chunk_start;
+      if chunk_len >= options.max_size_bytes ||
+        (chunk_len >= options.min_size_bytes && hash & mask == 0)
+      {
+        cuts.push(i + 1);
+        chunk_start = i + 1;
+        hash = 0;
+      }
+    }
+    if chunk_start < bytes.len() {
+      cuts.push(bytes.len());
+    }
+    cuts
+  }
+
   #[derive(Clone)]
   pub struct ByteStore {
     inner: Arc<InnerStore>,
@@ -142,13 +1090,46 @@ mod local {
     //  1. They may have different lifetimes.
     //  2. It's nice to know whether we should be able to parse something as a proto.
     directory_store: Database,
+    // Chunk manifests, keyed by the Fingerprint of the whole (unchunked) plaintext, stored in
+    // their own database rather than alongside plain values in file_store. A magic-prefix scheme
+    // in a shared database can never fully rule out a plain file whose content coincidentally
+    // looks like a manifest; a separate database makes "is this a manifest" a question of which
+    // database answered, not of sniffing the value.
+    manifest_store: Database,
+    chunking: Option<ChunkingOptions>,
+    // Held in read mode around every individual write, and in write mode for the duration of a
+    // whole garbage_collect pass (mark and sweep together), so that nothing can be written to
+    // either database while garbage_collect is deciding and acting on what's live. See
+    // Store::garbage_collect for why LMDB's single-writer guarantee alone isn't enough.
+    gc_lock: Arc<RwLock<()>>,
   }
 
   impl ByteStore {
     pub fn new<P: AsRef<Path>>(path: P, pool: Arc<ResettablePool>) -> Result<ByteStore, String> {
-      // 2 DBs; one for file contents, one for directories.
+      ByteStore::new_maybe_chunked(path, pool, None)
+    }
+
+    ///
+    /// Like new, but splits file blobs larger than chunking.min_size_bytes into content-defined
+    /// chunks instead of storing them as a single LMDB value. The externally-visible Digest is
+    /// unaffected: it is always the Fingerprint of the full plaintext.
+    ///
+    pub fn new_with_chunking<P: AsRef<Path>>(
+      path: P,
+      pool: Arc<ResettablePool>,
+      chunking: ChunkingOptions,
+    ) -> Result<ByteStore, String> {
+      ByteStore::new_maybe_chunked(path, pool, Some(chunking))
+    }
+
+    fn new_maybe_chunked<P: AsRef<Path>>(
+      path: P,
+      pool: Arc<ResettablePool>,
+      chunking: Option<ChunkingOptions>,
+    ) -> Result<ByteStore, String> {
+      // 3 DBs: file contents, directories, and chunk manifests.
       let env = Environment::new()
-        .set_max_dbs(2)
+        .set_max_dbs(3)
         .set_map_size(16 * 1024 * 1024 * 1024)
         .open(path.as_ref())
         .map_err(|e| format!("Error making env: {}", e.description()))?;
@@ -165,16 +1146,35 @@ mod local {
             e.description()
           )
         })?;
+      let manifest_database = env
+        .create_db(Some("manifests"), DatabaseFlags::empty())
+        .map_err(|e| {
+          format!(
+            "Error creating/opening manifests database: {}",
+            e.description()
+          )
+        })?;
       Ok(ByteStore {
         inner: Arc::new(InnerStore {
           env: env,
           pool: pool,
           file_store: file_database,
           directory_store: directory_database,
+          manifest_store: manifest_database,
+          chunking: chunking,
+          gc_lock: Arc::new(RwLock::new(())),
         }),
       })
     }
 
+    pub fn pool(&self) -> Arc<ResettablePool> {
+      self.inner.pool.clone()
+    }
+
+    pub fn gc_lock(&self) -> Arc<RwLock<()>> {
+      self.inner.gc_lock.clone()
+    }
+
     fn load_bytes_with<T: Send + 'static, F: Fn(&[u8]) -> T + Send + Sync + 'static>(
       &self,
       fingerprint: Fingerprint,
@@ -204,47 +1204,329 @@ mod local {
     fn store_bytes(&self, bytes: Vec<u8>, db: Database) -> CpuFuture<Fingerprint, String> {
       let store = self.clone();
       self.inner.pool.spawn_fn(move || {
+        let _read_guard = store
+          .inner
+          .gc_lock
+          .read()
+          .map_err(|err| format!("Store gc_lock was poisoned: {}", err))?;
         let fingerprint = {
           let mut hasher = Sha256::default();
           hasher.input(&bytes);
           Fingerprint::from_bytes_unsafe(hasher.fixed_result().as_slice())
         };
 
-        let put_res = store.inner.env.begin_rw_txn().and_then(|mut txn| {
-          txn.put(db, &fingerprint, &bytes, NO_OVERWRITE).and_then(
-            |()| txn.commit(),
+        let put_res = store.inner.env.begin_rw_txn().and_then(|mut txn| {
+          txn.put(db, &fingerprint, &bytes, NO_OVERWRITE).and_then(
+            |()| txn.commit(),
+          )
+        });
+
+        match put_res {
+          Ok(()) => Ok(fingerprint),
+          Err(KeyExist) => Ok(fingerprint),
+          Err(err) => Err(format!(
+            "Error storing fingerprint {}: {}",
+            fingerprint,
+            err.description()
+          )),
+        }
+      })
+    }
+
+    // Store bytes under an already-known fingerprint (rather than hashing them ourselves), so
+    // that a chunk or a manifest can be written under the key the caller has already computed.
+    // Idempotent, like store_bytes: a pre-existing value at that key is left untouched.
+    fn put_bytes_at(
+      env: &Environment,
+      db: Database,
+      fingerprint: &Fingerprint,
+      bytes: &[u8],
+    ) -> Result<(), String> {
+      let put_res = env.begin_rw_txn().and_then(|mut txn| {
+        txn.put(db, fingerprint, &bytes, NO_OVERWRITE).and_then(
+          |()| txn.commit(),
+        )
+      });
+      match put_res {
+        Ok(()) => Ok(()),
+        Err(KeyExist) => Ok(()),
+        Err(err) => Err(format!(
+          "Error storing fingerprint {}: {}",
+          fingerprint,
+          err.description()
+        )),
+      }
+    }
+
+    ///
+    /// Split bytes into content-defined chunks, store each chunk under its own Fingerprint (so
+    /// identical chunks shared between files are only stored once), and store a manifest of the
+    /// ordered chunk Fingerprints under the Fingerprint of the whole plaintext. If bytes turns out
+    /// not to actually split (e.g. it is smaller than one chunk), falls back to storing it as a
+    /// single monolithic value, exactly as the unchunked path would.
+    ///
+    fn store_chunked_file_bytes(
+      &self,
+      bytes: Vec<u8>,
+      chunking: ChunkingOptions,
+    ) -> CpuFuture<Digest, String> {
+      let store = self.clone();
+      self.inner.pool.spawn_fn(move || {
+        let _read_guard = store
+          .inner
+          .gc_lock
+          .read()
+          .map_err(|err| format!("Store gc_lock was poisoned: {}", err))?;
+        let len = bytes.len();
+        let cut_points = chunk_cut_points(&bytes, chunking);
+        if cut_points.len() <= 1 {
+          let fingerprint = {
+            let mut hasher = Sha256::default();
+            hasher.input(&bytes);
+            Fingerprint::from_bytes_unsafe(hasher.fixed_result().as_slice())
+          };
+          ByteStore::put_bytes_at(&store.inner.env, store.inner.file_store, &fingerprint, &bytes)?;
+          return Ok(Digest(fingerprint, len));
+        }
+
+        let mut chunk_start = 0;
+        let mut chunk_fingerprints = Vec::with_capacity(cut_points.len());
+        for chunk_end in cut_points {
+          let chunk = &bytes[chunk_start..chunk_end];
+          let chunk_fingerprint = {
+            let mut hasher = Sha256::default();
+            hasher.input(chunk);
+            Fingerprint::from_bytes_unsafe(hasher.fixed_result().as_slice())
+          };
+          ByteStore::put_bytes_at(
+            &store.inner.env,
+            store.inner.file_store,
+            &chunk_fingerprint,
+            chunk,
+          )?;
+          chunk_fingerprints.push(chunk_fingerprint);
+          chunk_start = chunk_end;
+        }
+
+        let fingerprint = {
+          let mut hasher = Sha256::default();
+          hasher.input(&bytes);
+          Fingerprint::from_bytes_unsafe(hasher.fixed_result().as_slice())
+        };
+        let manifest = encode_manifest(&chunk_fingerprints, len);
+        ByteStore::put_bytes_at(
+          &store.inner.env,
+          store.inner.manifest_store,
+          &fingerprint,
+          &manifest,
+        )?;
+        Ok(Digest(fingerprint, len))
+      })
+    }
+
+    // A file Fingerprint with an entry in manifest_store is live iff the whole file is live, but
+    // the individual chunks it names are the keys actually stored in file_store, so sweeping on
+    // live_files directly would delete every chunk out from under a live chunked file. Expand the
+    // live set to include them first.
+    fn expand_live_file_keys(
+      &self,
+      live_files: &HashSet<Fingerprint>,
+    ) -> Result<HashSet<Fingerprint>, String> {
+      let ro_txn = self.inner.env.begin_ro_txn().map_err(|err| {
+        format!(
+          "Failed to begin read transaction: {}",
+          err.description().to_string()
+        )
+      })?;
+      let mut expanded = HashSet::with_capacity(live_files.len());
+      for fingerprint in live_files {
+        expanded.insert(*fingerprint);
+        match ro_txn.get(self.inner.manifest_store, fingerprint) {
+          Ok(bytes) => {
+            let (chunk_fingerprints, _total_len) = decode_manifest(bytes)?;
+            expanded.extend(chunk_fingerprints);
+          }
+          Err(NotFound) => (),
+          Err(err) => {
+            return Err(format!(
+              "Error reading fingerprint {} during garbage collection: {}",
+              fingerprint,
+              err.description()
+            ))
+          }
+        }
+      }
+      Ok(expanded)
+    }
+
+    // Deletes every key in db that is not in live, in a single write transaction, unless dry_run
+    // is set, in which case nothing is mutated and the transaction is aborted. Relies on there
+    // only ever being one live write transaction against env at a time, so a blob being written
+    // concurrently either fully precedes or fully follows this sweep, never straddling it.
+    fn sweep(&self, db: Database, live: &HashSet<Fingerprint>, dry_run: bool) -> Result<GcStats, String> {
+      let mut txn = self.inner.env.begin_rw_txn().map_err(|err| {
+        format!(
+          "Failed to begin write transaction for garbage collection: {}",
+          err.description()
+        )
+      })?;
+
+      let mut dead = Vec::new();
+      {
+        let mut cursor = txn.open_rw_cursor(db).map_err(|err| {
+          format!("Failed to open cursor for garbage collection: {}", err.description())
+        })?;
+        for (key, value) in cursor.iter_start() {
+          let fingerprint = Fingerprint::from_bytes_unsafe(key);
+          if !live.contains(&fingerprint) {
+            dead.push((fingerprint, value.len()));
+          }
+        }
+      }
+
+      let mut stats = GcStats {
+        keys_removed: 0,
+        bytes_reclaimed: 0,
+      };
+      if dry_run {
+        txn.abort();
+        for (_, len) in dead {
+          stats.keys_removed += 1;
+          stats.bytes_reclaimed += len;
+        }
+      } else {
+        for (fingerprint, len) in dead {
+          txn.del(db, &fingerprint, None).map_err(|err| {
+            format!(
+              "Error deleting fingerprint {} during garbage collection: {}",
+              fingerprint,
+              err.description()
+            )
+          })?;
+          stats.keys_removed += 1;
+          stats.bytes_reclaimed += len;
+        }
+        txn.commit().map_err(|err| {
+          format!(
+            "Failed to commit garbage collection transaction: {}",
+            err.description()
           )
-        });
+        })?;
+      }
+      Ok(stats)
+    }
 
-        match put_res {
-          Ok(()) => Ok(fingerprint),
-          Err(KeyExist) => Ok(fingerprint),
-          Err(err) => Err(format!(
-            "Error storing fingerprint {}: {}",
-            fingerprint,
-            err.description()
-          )),
-        }
-      })
+    ///
+    /// Deletes every file, manifest and Directory value whose Fingerprint isn't in live_files /
+    /// live_directories. Chunk manifests are expanded automatically, so live_files should be the
+    /// Fingerprints of whole (unchunked) file contents, not individual chunks. Dead manifests are
+    /// swept alongside the file chunks they would have named, and folded into the same stats:
+    /// from the outside, a chunked file and a monolithic one are both just "a file".
+    ///
+    pub fn garbage_collect(
+      &self,
+      live_files: &HashSet<Fingerprint>,
+      live_directories: &HashSet<Fingerprint>,
+      dry_run: bool,
+    ) -> Result<(GcStats, GcStats), String> {
+      let expanded_live_files = self.expand_live_file_keys(live_files)?;
+      let chunk_stats = self.sweep(self.inner.file_store, &expanded_live_files, dry_run)?;
+      let manifest_stats = self.sweep(self.inner.manifest_store, live_files, dry_run)?;
+      let file_stats = GcStats {
+        keys_removed: chunk_stats.keys_removed + manifest_stats.keys_removed,
+        bytes_reclaimed: chunk_stats.bytes_reclaimed + manifest_stats.bytes_reclaimed,
+      };
+      let directory_stats = self.sweep(self.inner.directory_store, live_directories, dry_run)?;
+      Ok((file_stats, directory_stats))
     }
   }
 
+  ///
+  /// How many keys (and how many bytes of value storage) a single garbage_collect sweep removed
+  /// (or, in a dry run, would remove) from one of the file/directory databases.
+  ///
+  #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+  pub struct GcStats {
+    pub keys_removed: usize,
+    pub bytes_reclaimed: usize,
+  }
+
   impl super::ByteStore for ByteStore {
     fn store_file_bytes(&self, bytes: Vec<u8>) -> BoxFuture<Digest, String> {
-      let len = bytes.len();
-      self
-        .store_bytes(bytes, self.inner.file_store.clone())
-        .map(move |fingerprint| Digest(fingerprint, len))
-        .to_boxed()
+      match self.inner.chunking {
+        Some(chunking) if bytes.len() > chunking.min_size_bytes => {
+          self.store_chunked_file_bytes(bytes, chunking).to_boxed()
+        }
+        _ => {
+          let len = bytes.len();
+          self
+            .store_bytes(bytes, self.inner.file_store.clone())
+            .map(move |fingerprint| Digest(fingerprint, len))
+            .to_boxed()
+        }
+      }
     }
 
     fn load_file_bytes_with<T: Send + 'static, F: Fn(&[u8]) -> T + Send + Sync + 'static>(
       &self,
-      fingerprint: Fingerprint,
+      digest: Digest,
       f: Arc<F>,
     ) -> BoxFuture<Option<T>, String> {
+      // LMDB is keyed purely by Fingerprint, so digest.1 (the size) is only meaningful to a
+      // remote CAS read; it is ignored here.
+      let fingerprint = digest.0;
+      let store = self.inner.clone();
       self
-        .load_bytes_with(fingerprint, self.inner.file_store, f)
+        .inner
+        .pool
+        .spawn_fn(move || {
+          let ro_txn = store.env.begin_ro_txn().map_err(|err| {
+            format!(
+              "Failed to begin read transaction: {}",
+              err.description().to_string()
+            )
+          });
+          ro_txn.and_then(|txn| match txn.get(store.manifest_store, &fingerprint) {
+            Ok(manifest_bytes) => {
+              let (chunk_fingerprints, total_len) = decode_manifest(manifest_bytes)?;
+              let mut whole = Vec::with_capacity(total_len);
+              for chunk_fingerprint in &chunk_fingerprints {
+                match txn.get(store.file_store, chunk_fingerprint) {
+                  Ok(chunk_bytes) => whole.extend_from_slice(chunk_bytes),
+                  Err(NotFound) => {
+                    return Err(format!(
+                      "LMDB corruption: chunk {} referenced by manifest for {} is missing",
+                      chunk_fingerprint,
+                      fingerprint
+                    ))
+                  }
+                  Err(err) => {
+                    return Err(format!(
+                      "Error loading chunk {}: {}",
+                      chunk_fingerprint,
+                      err.description().to_string()
+                    ))
+                  }
+                }
+              }
+              Ok(Some(f(&whole)))
+            }
+            Err(NotFound) => match txn.get(store.file_store, &fingerprint) {
+              Ok(bytes) => Ok(Some(f(bytes))),
+              Err(NotFound) => Ok(None),
+              Err(err) => Err(format!(
+                "Error loading fingerprint {}: {}",
+                fingerprint,
+                err.description().to_string()
+              )),
+            },
+            Err(err) => Err(format!(
+              "Error loading fingerprint {}: {}",
+              fingerprint,
+              err.description().to_string()
+            )),
+          })
+        })
         .to_boxed()
     }
 
@@ -280,11 +1562,11 @@ mod local {
       F: Fn(&[u8]) -> T + Send + Sync + 'static,
     >(
       &self,
-      fingerprint: Fingerprint,
+      digest: Digest,
       f: Arc<F>,
     ) -> BoxFuture<Option<T>, String> {
       self
-        .load_bytes_with(fingerprint.clone(), self.inner.directory_store, f)
+        .load_bytes_with(digest.0, self.inner.directory_store, f)
         .to_boxed()
     }
   }
@@ -294,16 +1576,17 @@ mod local {
     extern crate tempdir;
 
     use futures::Future;
-    use super::{ByteStore, Fingerprint, ResettablePool};
+    use super::{ByteStore, Digest, Fingerprint, ResettablePool};
     use super::super::ByteStore as _ByteStore;
     use lmdb::{DatabaseFlags, Environment, Transaction, WriteFlags};
     use protobuf::Message;
+    use std::collections::HashSet;
     use std::path::Path;
     use std::sync::Arc;
     use tempdir::TempDir;
 
-    use super::super::tests::{DIRECTORY_HASH, HASH, digest, directory, directory_fingerprint,
-                              fingerprint, str_bytes};
+    use super::super::tests::{DIRECTORY_HASH, HASH, digest, directory, directory_digest,
+                              directory_fingerprint, fingerprint, str_bytes};
 
     #[test]
     fn save_file() {
@@ -346,8 +1629,10 @@ mod local {
         })
         .unwrap();
 
+      let bogus_digest = Digest(fingerprint, bogus_value.len());
+
       assert_eq!(
-        load_file_bytes(&new_store(dir.path()), fingerprint),
+        load_file_bytes(&new_store(dir.path()), bogus_digest.clone()),
         Ok(Some(bogus_value.clone()))
       );
 
@@ -357,7 +1642,7 @@ mod local {
       );
 
       assert_eq!(
-        load_file_bytes(&new_store(dir.path()), fingerprint),
+        load_file_bytes(&new_store(dir.path()), bogus_digest),
         Ok(Some(bogus_value.clone()))
       );
     }
@@ -368,15 +1653,15 @@ mod local {
       let dir = TempDir::new("store").unwrap();
 
       let store = new_store(dir.path());
-      let hash = store.store_file_bytes(data.clone()).wait().unwrap();
-      assert_eq!(load_file_bytes(&store, hash.0), Ok(Some(data)));
+      let digest = store.store_file_bytes(data.clone()).wait().unwrap();
+      assert_eq!(load_file_bytes(&store, digest), Ok(Some(data)));
     }
 
     #[test]
     fn missing_file() {
       let dir = TempDir::new("store").unwrap();
       assert_eq!(
-        load_file_bytes(&new_store(dir.path()), fingerprint()),
+        load_file_bytes(&new_store(dir.path()), digest()),
         Ok(None)
       );
     }
@@ -396,7 +1681,7 @@ mod local {
       );
 
       assert_eq!(
-        load_directory_proto_bytes(&new_store(dir.path()), directory_fingerprint()),
+        load_directory_proto_bytes(&new_store(dir.path()), directory_digest()),
         Ok(Some(directory().write_to_bytes().unwrap()))
       );
     }
@@ -406,7 +1691,7 @@ mod local {
       let dir = TempDir::new("store").unwrap();
 
       assert_eq!(
-        load_directory_proto_bytes(&new_store(dir.path()), directory_fingerprint()),
+        load_directory_proto_bytes(&new_store(dir.path()), directory_digest()),
         Ok(None)
       );
     }
@@ -421,38 +1706,359 @@ mod local {
         .unwrap();
 
       assert_eq!(
-        load_directory_proto_bytes(&new_store(dir.path()), fingerprint()),
+        load_directory_proto_bytes(&new_store(dir.path()), digest()),
         Ok(None)
       );
     }
 
+    #[test]
+    fn roundtrip_chunked_file() {
+      // Bigger than the default max chunk size, so this is guaranteed to split into more than one
+      // chunk regardless of where the content-defined cut points land.
+      let data: Vec<u8> = (0..10 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+      let dir = TempDir::new("store").unwrap();
+
+      let store = new_chunked_store(dir.path());
+      let digest = store.store_file_bytes(data.clone()).wait().unwrap();
+      assert_eq!(load_file_bytes(&store, digest), Ok(Some(data)));
+    }
+
+    #[test]
+    fn small_file_is_not_chunked() {
+      // Smaller than min_size_bytes, so it should be stored exactly like the unchunked path,
+      // making its Digest identical regardless of whether chunking is enabled.
+      let data = str_bytes();
+      let dir = TempDir::new("store").unwrap();
+
+      assert_eq!(
+        new_chunked_store(dir.path())
+          .store_file_bytes(data.clone())
+          .wait(),
+        Ok(digest())
+      );
+    }
+
+    #[test]
+    fn garbage_collect_expands_chunked_manifests() {
+      // Bigger than the default max chunk size, so this is guaranteed to split into more than one
+      // chunk regardless of where the content-defined cut points land.
+      let data: Vec<u8> = (0..10 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+      let dir = TempDir::new("store").unwrap();
+      let store = new_chunked_store(dir.path());
+
+      let digest = store.store_file_bytes(data.clone()).wait().unwrap();
+
+      let mut live_files = HashSet::new();
+      live_files.insert(digest.0);
+      store
+        .garbage_collect(&live_files, &HashSet::new(), false)
+        .unwrap();
+
+      assert_eq!(load_file_bytes(&store, digest), Ok(Some(data)));
+    }
+
+    #[test]
+    fn garbage_collect_sweeps_dead_manifest_and_its_chunks() {
+      let data: Vec<u8> = (0..10 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+      let dir = TempDir::new("store").unwrap();
+      let store = new_chunked_store(dir.path());
+
+      let digest = store.store_file_bytes(data.clone()).wait().unwrap();
+
+      store
+        .garbage_collect(&HashSet::new(), &HashSet::new(), false)
+        .unwrap();
+
+      assert_eq!(load_file_bytes(&store, digest), Ok(None));
+    }
+
     fn new_store<P: AsRef<Path>>(dir: P) -> ByteStore {
       ByteStore::new(dir, Arc::new(ResettablePool::new("test-pool-".to_string()))).unwrap()
     }
 
-    fn load_file_bytes(
-      store: &ByteStore,
-      fingerprint: Fingerprint,
-    ) -> Result<Option<Vec<u8>>, String> {
+    fn new_chunked_store<P: AsRef<Path>>(dir: P) -> ByteStore {
+      ByteStore::new_with_chunking(
+        dir,
+        Arc::new(ResettablePool::new("test-pool-".to_string())),
+        super::ChunkingOptions::default(),
+      ).unwrap()
+    }
+
+    fn load_file_bytes(store: &ByteStore, digest: Digest) -> Result<Option<Vec<u8>>, String> {
       store
-        .load_file_bytes_with(fingerprint, Arc::new(|bytes: &[u8]| bytes.to_vec()))
+        .load_file_bytes_with(digest, Arc::new(|bytes: &[u8]| bytes.to_vec()))
         .wait()
     }
 
     fn load_directory_proto_bytes(
       store: &ByteStore,
-      fingerprint: Fingerprint,
+      digest: Digest,
     ) -> Result<Option<Vec<u8>>, String> {
       store
-        .load_directory_proto_bytes_with(fingerprint, Arc::new(|bytes: &[u8]| bytes.to_vec()))
+        .load_directory_proto_bytes_with(digest, Arc::new(|bytes: &[u8]| bytes.to_vec()))
         .wait()
     }
   }
 }
 
+mod remote {
+  use super::{digest_from_proto, Digest};
+
+  use bazel_protos;
+  use bazel_protos::remote_execution_grpc::ContentAddressableStorageClient;
+  use bazel_protos::bytestream_grpc::ByteStreamClient;
+  use boxfuture::{Boxable, BoxFuture};
+  use digest::{Digest as DigestTrait, FixedOutput};
+  use futures::{future, Future, Stream};
+  use grpcio;
+  use protobuf::core::Message;
+  use sha2::Sha256;
+  use std::error::Error;
+  use std::sync::Arc;
+  use uuid::Uuid;
+
+  use hash::Fingerprint;
+
+  #[derive(Clone)]
+  pub struct ByteStore {
+    instance_name: Option<String>,
+    chunk_size_bytes: usize,
+    cas_client: Arc<ContentAddressableStorageClient>,
+    bytestream_client: Arc<ByteStreamClient>,
+  }
+
+  impl ByteStore {
+    pub fn new(
+      cas_address: &str,
+      instance_name: Option<String>,
+      chunk_size_bytes: usize,
+    ) -> ByteStore {
+      let env = Arc::new(grpcio::Environment::new(1));
+      let channel = grpcio::ChannelBuilder::new(env).connect(cas_address);
+      ByteStore {
+        instance_name: instance_name,
+        chunk_size_bytes: chunk_size_bytes,
+        cas_client: Arc::new(ContentAddressableStorageClient::new(channel.clone())),
+        bytestream_client: Arc::new(ByteStreamClient::new(channel)),
+      }
+    }
+
+    // Per https://github.com/bazelbuild/remote-apis, resource names for uploads are namespaced
+    // under the (optional) instance name and a per-upload uuid, so that concurrent uploads of the
+    // same blob by different clients don't collide.
+    fn write_resource_name(&self, fingerprint: &Fingerprint, len: usize) -> String {
+      format!(
+        "{}uploads/{}/blobs/{}/{}",
+        self
+          .instance_name
+          .as_ref()
+          .map(|instance_name| format!("{}/", instance_name))
+          .unwrap_or_default(),
+        Uuid::new_v4(),
+        fingerprint.to_hex(),
+        len
+      )
+    }
+
+    // Per https://github.com/bazelbuild/remote-apis, resource names for reads carry no upload
+    // uuid (there is nothing being uploaded) and must name the blob's real size, not an
+    // approximation of it: "{instance_name}/blobs/{hash}/{size}".
+    fn read_resource_name(&self, digest: &Digest) -> String {
+      format!(
+        "{}blobs/{}/{}",
+        self
+          .instance_name
+          .as_ref()
+          .map(|instance_name| format!("{}/", instance_name))
+          .unwrap_or_default(),
+        digest.0.to_hex(),
+        digest.1
+      )
+    }
+
+    fn find_missing_blobs(
+      &self,
+      digests: Vec<Digest>,
+    ) -> BoxFuture<Vec<Digest>, String> {
+      let mut request = bazel_protos::remote_execution::FindMissingBlobsRequest::new();
+      if let Some(ref instance_name) = self.instance_name {
+        request.set_instance_name(instance_name.clone());
+      }
+      request.set_blob_digests(protobuf::RepeatedField::from_vec(
+        digests
+          .iter()
+          .cloned()
+          .map(|digest| digest.into())
+          .collect(),
+      ));
+
+      self
+        .cas_client
+        .find_missing_blobs_async(&request)
+        .map_err(|err| format!("Error calling FindMissingBlobs: {}", err.description()))
+        .and_then(|receiver| receiver.map_err(|err| format!("Error calling FindMissingBlobs: {}", err.description())))
+        .and_then(|response| {
+          response
+            .get_missing_blob_digests()
+            .iter()
+            .map(digest_from_proto)
+            .collect::<Result<Vec<_>, _>>()
+        })
+        .to_boxed()
+    }
+
+    fn store_bytes_at(&self, bytes: Vec<u8>, digest: Digest) -> BoxFuture<(), String> {
+      let resource_name = self.write_resource_name(&digest.0, digest.1);
+      let chunk_size_bytes = self.chunk_size_bytes;
+
+      let write_client = match self.bytestream_client.write_opt(grpcio::CallOption::default()) {
+        Ok(client) => client,
+        Err(err) => {
+          return future::err(format!("Error starting ByteStream.Write: {}", err.description()))
+            .to_boxed()
+        }
+      };
+
+      future::result(bytes.chunks(chunk_size_bytes).enumerate().map(|(index, chunk)| {
+        let mut req = bazel_protos::bytestream::WriteRequest::new();
+        req.set_resource_name(resource_name.clone());
+        req.set_write_offset((index * chunk_size_bytes) as i64);
+        req.set_finish_write((index + 1) * chunk_size_bytes >= bytes.len());
+        req.set_data(chunk.to_vec());
+        req
+      }).collect::<Vec<_>>()).and_then(move |requests| {
+        write_client
+          .send_all(futures::stream::iter_ok(requests))
+          .and_then(|(client, _)| client.close_and_receive())
+          .map(|_| ())
+          .map_err(|err| format!("Error writing blob via ByteStream.Write: {}", err.description()))
+      })
+        .to_boxed()
+    }
+  }
+
+  impl super::ByteStore for ByteStore {
+    fn store_file_bytes(&self, bytes: Vec<u8>) -> BoxFuture<Digest, String> {
+      let store = self.clone();
+      let fingerprint = {
+        let mut hasher = Sha256::default();
+        hasher.input(&bytes);
+        Fingerprint::from_bytes_unsafe(hasher.fixed_result().as_slice())
+      };
+      let digest = Digest(fingerprint, bytes.len());
+
+      store
+        .find_missing_blobs(vec![digest.clone()])
+        .and_then(move |missing| {
+          if missing.is_empty() {
+            future::ok(()).to_boxed()
+          } else {
+            store.store_bytes_at(bytes, digest.clone())
+          }
+        })
+        .map(move |()| digest)
+        .to_boxed()
+    }
+
+    fn load_file_bytes_with<T: Send + 'static, F: Fn(&[u8]) -> T + Send + Sync + 'static>(
+      &self,
+      digest: Digest,
+      f: Arc<F>,
+    ) -> BoxFuture<Option<T>, String> {
+      let resource_name = self.read_resource_name(&digest);
+      let mut req = bazel_protos::bytestream::ReadRequest::new();
+      req.set_resource_name(resource_name);
+
+      self
+        .bytestream_client
+        .read(&req)
+        .map_err(|err| format!("Error calling ByteStream.Read: {}", err.description()))
+        .map(|stream| {
+          stream
+            .map(|response| response.get_data().to_vec())
+            .collect()
+            .map(|chunks| Some(f(&chunks.concat())))
+            .map_err(|err| format!("Error reading blob via ByteStream.Read: {}", err.description()))
+        })
+        .and_then(|fut| fut)
+        .to_boxed()
+    }
+
+    ///
+    /// Store the Directory proto by uploading its serialized bytes through the same CAS endpoints
+    /// used for file contents; remote CAS has no notion of a separate directories namespace.
+    ///
+    fn record_directory(
+      &self,
+      directory: &bazel_protos::remote_execution::Directory,
+    ) -> BoxFuture<Digest, String> {
+      let store = self.clone();
+      future::result(directory.write_to_bytes().map_err(|e| {
+        format!(
+          "Error serializing directory proto {:?}: {}",
+          directory,
+          e.description()
+        )
+      })).and_then(move |bytes| store.store_file_bytes(bytes))
+        .to_boxed()
+    }
+
+    fn load_directory_proto_bytes_with<
+      T: Send + 'static,
+      F: Fn(&[u8]) -> T + Send + Sync + 'static,
+    >(
+      &self,
+      digest: Digest,
+      f: Arc<F>,
+    ) -> BoxFuture<Option<T>, String> {
+      self.load_file_bytes_with(digest, f)
+    }
+  }
+
+  #[cfg(test)]
+  mod tests {
+    use super::{ByteStore, Digest};
+    use hash::Fingerprint;
+
+    fn new_store(instance_name: Option<String>) -> ByteStore {
+      ByteStore::new("localhost:0", instance_name, 1024)
+    }
+
+    #[test]
+    fn read_resource_name_has_no_upload_uuid_and_uses_the_real_size() {
+      let fingerprint = Fingerprint::from_bytes_unsafe(&[0; 32]);
+      let digest = Digest(fingerprint, 1337);
+
+      assert_eq!(
+        new_store(None).read_resource_name(&digest),
+        format!("blobs/{}/1337", fingerprint)
+      );
+      assert_eq!(
+        new_store(Some("theinstance".to_string())).read_resource_name(&digest),
+        format!("theinstance/blobs/{}/1337", fingerprint)
+      );
+    }
+
+    #[test]
+    fn write_resource_name_is_distinct_from_read_resource_name() {
+      let fingerprint = Fingerprint::from_bytes_unsafe(&[0; 32]);
+      let digest = Digest(fingerprint, 1337);
+      let store = new_store(None);
+
+      let write_resource_name = store.write_resource_name(&digest.0, digest.1);
+      let read_resource_name = store.read_resource_name(&digest);
+
+      assert!(write_resource_name.contains("uploads/"));
+      assert!(!read_resource_name.contains("uploads/"));
+      assert_eq!(read_resource_name, format!("blobs/{}/1337", fingerprint));
+    }
+  }
+}
+
 #[cfg(test)]
 mod tests {
   use bazel_protos;
+  use protobuf::Message;
   use super::{Digest, Fingerprint};
 
   pub const STR: &str = "European Burmese";
@@ -493,6 +2099,10 @@ c0033144c785a94d3ebd82baa931cd16";
     Fingerprint::from_hex_string(DIRECTORY_HASH).unwrap()
   }
 
+  pub fn directory_digest() -> Digest {
+    Digest(directory_fingerprint(), directory().write_to_bytes().unwrap().len())
+  }
+
   #[test]
   fn digest_to_bazel_digest() {
     let digest = Digest(Fingerprint::from_hex_string(HASH).unwrap(), 16);
@@ -502,3 +2112,249 @@ c0033144c785a94d3ebd82baa931cd16";
     assert_eq!(bazel_digest, digest.into());
   }
 }
+
+#[cfg(test)]
+mod store_tests {
+  extern crate tempdir;
+
+  use bazel_protos;
+  use futures::Future;
+  use std::fs;
+  use std::os::unix::fs::{symlink, PermissionsExt};
+  use std::path::Path;
+  use std::sync::Arc;
+  use tempdir::TempDir;
+
+  use super::{GarbageCollectionSummary, RemoteWriteMode, Store};
+  use super::tests::{DIRECTORY_HASH, directory, directory_fingerprint, fingerprint};
+  use pool::ResettablePool;
+
+  fn new_store<P: AsRef<Path>>(dir: P) -> Store {
+    Store::new(dir, Arc::new(ResettablePool::new("test-pool-".to_string()))).unwrap()
+  }
+
+  #[test]
+  fn record_directory_recursively_rejects_non_canonical_directory() {
+    let dir = TempDir::new("store").unwrap();
+    let store = new_store(dir.path());
+
+    let mut unsorted = bazel_protos::remote_execution::Directory::new();
+    unsorted.mut_directories().push({
+      let mut node = bazel_protos::remote_execution::DirectoryNode::new();
+      node.set_name("zeta".to_string());
+      node.set_digest({
+        let mut digest = bazel_protos::remote_execution::Digest::new();
+        digest.set_hash(DIRECTORY_HASH.to_string());
+        digest
+      });
+      node
+    });
+    unsorted.mut_directories().push({
+      let mut node = bazel_protos::remote_execution::DirectoryNode::new();
+      node.set_name("alpha".to_string());
+      node.set_digest({
+        let mut digest = bazel_protos::remote_execution::Digest::new();
+        digest.set_hash(DIRECTORY_HASH.to_string());
+        digest
+      });
+      node
+    });
+
+    let result = store.record_directory_recursively(&unsorted).wait();
+    assert!(result.is_err());
+  }
+
+  #[test]
+  fn record_directory_recursively_rejects_missing_child_directory() {
+    let dir = TempDir::new("store").unwrap();
+    let store = new_store(dir.path());
+
+    let mut root = bazel_protos::remote_execution::Directory::new();
+    root.mut_directories().push({
+      let mut node = bazel_protos::remote_execution::DirectoryNode::new();
+      node.set_name("child".to_string());
+      node.set_digest({
+        let mut digest = bazel_protos::remote_execution::Digest::new();
+        digest.set_hash(DIRECTORY_HASH.to_string());
+        digest
+      });
+      node
+    });
+
+    let result = store.record_directory_recursively(&root).wait();
+    assert!(result.is_err());
+  }
+
+  #[test]
+  fn record_directory_recursively_rejects_missing_file() {
+    let dir = TempDir::new("store").unwrap();
+    let store = new_store(dir.path());
+
+    // directory() references a file that we never store, so closure validation should fail even
+    // though the Directory proto itself is canonical.
+    let result = store.record_directory_recursively(&directory()).wait();
+    assert!(result.is_err());
+  }
+
+  #[test]
+  fn record_directory_recursively_succeeds_when_closure_is_present() {
+    let dir = TempDir::new("store").unwrap();
+    let store = new_store(dir.path());
+
+    store
+      .store_file_bytes(super::tests::str_bytes())
+      .wait()
+      .unwrap();
+
+    let result = store.record_directory_recursively(&directory()).wait();
+    assert_eq!(result.map(|digest| digest.0), Ok(directory_fingerprint()));
+  }
+
+  #[test]
+  fn ingest_and_materialize_directory_round_trips() {
+    let store_dir = TempDir::new("store").unwrap();
+    let store = new_store(store_dir.path());
+
+    let ingest_dir = TempDir::new("ingest").unwrap();
+    fs::write(ingest_dir.path().join("executable"), b"main").unwrap();
+    let mut permissions = fs::metadata(ingest_dir.path().join("executable"))
+      .unwrap()
+      .permissions();
+    permissions.set_mode(0o755);
+    fs::set_permissions(ingest_dir.path().join("executable"), permissions).unwrap();
+
+    fs::create_dir(ingest_dir.path().join("child")).unwrap();
+    fs::write(ingest_dir.path().join("child/roland"), b"European Burmese").unwrap();
+
+    symlink("roland", ingest_dir.path().join("child/roland-link")).unwrap();
+
+    let digest = store.ingest_path(ingest_dir.path()).wait().unwrap();
+
+    let materialize_dir = TempDir::new("materialize").unwrap();
+    let destination = materialize_dir.path().join("output");
+    store
+      .materialize_directory(destination.clone(), digest)
+      .wait()
+      .unwrap();
+
+    assert_eq!(
+      fs::read(destination.join("executable")).unwrap(),
+      b"main".to_vec()
+    );
+    assert_eq!(
+      fs::metadata(destination.join("executable"))
+        .unwrap()
+        .permissions()
+        .mode() & 0o111,
+      0o111
+    );
+    assert_eq!(
+      fs::read(destination.join("child/roland")).unwrap(),
+      b"European Burmese".to_vec()
+    );
+    assert_eq!(
+      fs::read_link(destination.join("child/roland-link")).unwrap(),
+      Path::new("roland")
+    );
+  }
+
+  #[test]
+  fn materialize_directory_fails_cleanly_when_digest_is_missing() {
+    let dir = TempDir::new("store").unwrap();
+    let store = new_store(dir.path());
+
+    let materialize_dir = TempDir::new("materialize").unwrap();
+    let destination = materialize_dir.path().join("output");
+
+    let result = store
+      .materialize_directory(destination.clone(), super::tests::digest())
+      .wait();
+    assert!(result.is_err());
+    assert!(!destination.exists());
+  }
+
+  #[test]
+  fn garbage_collect_removes_unreferenced_content_and_keeps_roots() {
+    let dir = TempDir::new("store").unwrap();
+    let store = new_store(dir.path());
+
+    let live_digest = store
+      .store_file_bytes(super::tests::str_bytes())
+      .wait()
+      .unwrap();
+    let live_root = store.record_directory_recursively(&directory()).wait().unwrap();
+
+    let dead_digest = store.store_file_bytes(b"unreferenced".to_vec()).wait().unwrap();
+
+    let summary = store.garbage_collect(&[live_root.0], false).wait().unwrap();
+    assert_eq!(
+      summary,
+      GarbageCollectionSummary {
+        files_removed: 1,
+        file_bytes_reclaimed: dead_digest.1,
+        directories_removed: 0,
+        directory_bytes_reclaimed: 0,
+      }
+    );
+
+    assert_eq!(
+      store
+        .load_file_bytes_with(live_digest, |bytes: &[u8]| bytes.to_vec())
+        .wait(),
+      Ok(Some(super::tests::str_bytes()))
+    );
+    assert_eq!(
+      store
+        .load_file_bytes_with(dead_digest, |bytes: &[u8]| bytes.to_vec())
+        .wait(),
+      Ok(None)
+    );
+  }
+
+  #[test]
+  fn garbage_collect_dry_run_deletes_nothing() {
+    let dir = TempDir::new("store").unwrap();
+    let store = new_store(dir.path());
+
+    let dead_digest = store.store_file_bytes(b"unreferenced".to_vec()).wait().unwrap();
+
+    let summary = store.garbage_collect(&[], true).wait().unwrap();
+    assert_eq!(summary.files_removed, 1);
+    assert_eq!(summary.file_bytes_reclaimed, dead_digest.1);
+
+    assert_eq!(
+      store
+        .load_file_bytes_with(dead_digest, |bytes: &[u8]| bytes.to_vec())
+        .wait(),
+      Ok(Some(b"unreferenced".to_vec()))
+    );
+  }
+
+  #[test]
+  fn blocking_remote_push_failure_does_not_fail_the_local_write() {
+    let dir = TempDir::new("store").unwrap();
+    // Nothing is listening on this address, so every push to it fails to connect; the local
+    // write should still succeed and the caller should still get back the right Digest.
+    let store = Store::with_remote(
+      dir.path(),
+      Arc::new(ResettablePool::new("test-pool-".to_string())),
+      "127.0.0.1:1",
+      None,
+      1024,
+      RemoteWriteMode::Blocking,
+    ).unwrap();
+
+    let digest = store
+      .store_file_bytes(super::tests::str_bytes())
+      .wait()
+      .unwrap();
+    assert_eq!(digest.0, fingerprint());
+
+    assert_eq!(
+      store
+        .load_file_bytes_with(digest, |bytes: &[u8]| bytes.to_vec())
+        .wait(),
+      Ok(Some(super::tests::str_bytes()))
+    );
+  }
+}